@@ -1,113 +1,609 @@
-use std::os::unix::net::{UnixStream, UnixListener};
 use prost::Message;
-use std::io::{Read, Write};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
 use nomt::{Nomt, Session, Blake3Hasher, Options, KeyReadWrite};
 use sha2::Digest;
 use clap::Parser;
+use mio::{Events, Interest, Poll, Token};
+use mio::net::{UnixListener, UnixStream};
+use slab::Slab;
 
 // Import the generated types
 mod database_interface {
     include!(concat!(env!("OUT_DIR"), "/database_interface.rs"));
 }
-use database_interface::{Request, Response, RootResponse, GetResponse, PrefetchResponse, UpdateResponse, CloseResponse};
+use database_interface::{Request, Response, RootResponse, GetResponse, PrefetchResponse, UpdateResponse, CloseResponse, ProveResponse, ViewResponse, BeginResponse, StageResponse, CommitResponse, RollbackResponse, ExportResponse, ImportResponse, SnapshotEntry, Proof, MultiProof, SiblingPath, Terminal, Leaf, Empty};
+use nomt::proof::{PathProof, PathProofTerminal};
 
-fn handle_client(mut stream: UnixStream, nomt: &Nomt<Blake3Hasher>, session: Session) -> Session {
-    let mut next_session = session;
-    let mut buffer = vec![0; 1024*1024]; 
+const DEFAULT_EXPORT_LIMIT: usize = 1024;
 
-    loop {
-        // Read the length of the incoming message
-        let mut length_buffer = [0u8; 4];
-        if stream.read_exact(&mut length_buffer).is_err() {
-            break;
+// No single request needs anywhere near this; a client claiming more is
+// lying, so its connection is closed rather than let `read_buf` grow to
+// match.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+// Leaves staged across the chunks of an in-progress `Import`. Keyed by
+// `key_path` so a resent or overlapping chunk collapses to last-write-wins
+// instead of handing `commit` a duplicate key.
+type ImportBuffer = BTreeMap<[u8; 32], Option<Vec<u8>>>;
+
+// The listener owns token 0; connections take the remaining slab slots offset
+// by this so their tokens never collide with the listener's.
+const LISTENER: Token = Token(0);
+const CONN_OFFSET: usize = 1;
+
+// Convert the terminal node of a NOMT path proof into its protobuf form.
+fn terminal_to_proto(terminal: &PathProofTerminal) -> Terminal {
+    match terminal {
+        PathProofTerminal::Leaf(leaf) => Terminal {
+            terminal: Some(database_interface::terminal::Terminal::Leaf(Leaf {
+                key_path: leaf.key_path.to_vec(),
+                value_hash: leaf.value_hash.to_vec(),
+            })),
+        },
+        PathProofTerminal::Terminator(_) => Terminal {
+            terminal: Some(database_interface::terminal::Terminal::Empty(Empty {})),
+        },
+    }
+}
+
+// Convert a NOMT path proof for `key_path` into the protobuf `Proof`.
+fn proof_from_path(key_path: [u8; 32], path: PathProof) -> Proof {
+    Proof {
+        key_path: key_path.to_vec(),
+        terminal: Some(terminal_to_proto(&path.terminal)),
+        siblings: path.siblings.into_iter().map(|s| s.to_vec()).collect(),
+    }
+}
+
+// Fold per-key path proofs into one multiproof, deduplicating shared sibling
+// hashes into a single `nodes` table that each path references by index.
+fn multiproof_from_paths(paths: Vec<([u8; 32], PathProof)>) -> MultiProof {
+    let mut nodes: Vec<Vec<u8>> = Vec::new();
+    let mut index: HashMap<Vec<u8>, u32> = HashMap::new();
+    let mut key_paths = Vec::with_capacity(paths.len());
+    let mut terminals = Vec::with_capacity(paths.len());
+    let mut sibling_paths = Vec::with_capacity(paths.len());
+    for (key_path, path) in paths {
+        key_paths.push(key_path.to_vec());
+        terminals.push(terminal_to_proto(&path.terminal));
+        let refs = path
+            .siblings
+            .iter()
+            .map(|sibling| {
+                let bytes = sibling.to_vec();
+                *index.entry(bytes.clone()).or_insert_with(|| {
+                    let id = nodes.len() as u32;
+                    nodes.push(bytes);
+                    id
+                })
+            })
+            .collect();
+        sibling_paths.push(SiblingPath { nodes: refs });
+    }
+    MultiProof { key_paths, terminals, nodes, paths: sibling_paths }
+}
+
+// Interpret `bytes` as a 256-bit state root, if it is exactly that wide.
+fn root_array(bytes: &[u8]) -> Option<[u8; 32]> {
+    bytes.try_into().ok()
+}
+
+// The writer session, serialized across connections, plus the window of recent
+// roots reachable for historical reads. `history_window` must not exceed the
+// `rollback` depth configured on `Options`, or `history` would admit roots the
+// engine has already discarded.
+struct ServerState {
+    writer: Option<Session>,
+    history: VecDeque<[u8; 32]>,
+    history_window: usize,
+}
+
+impl ServerState {
+    fn new(writer: Session, root: [u8; 32], history_window: usize) -> Self {
+        let mut history = VecDeque::new();
+        history.push_back(root);
+        ServerState { writer: Some(writer), history, history_window }
+    }
+
+    fn record_root(&mut self, root: [u8; 32]) {
+        self.history.push_back(root);
+        while self.history.len() > self.history_window {
+            self.history.pop_front();
         }
-        let message_length = u32::from_be_bytes(length_buffer) as usize;
+    }
+
+    fn retains(&self, root: &[u8; 32]) -> bool {
+        self.history.contains(root)
+    }
+}
+
+// A client-scoped transaction. `base_root` is the root observed at `Begin`;
+// reads are pinned to it and `Commit` is refused if the live root has moved.
+struct Transaction {
+    base_root: [u8; 32],
+    writes: BTreeMap<[u8; 32], Option<Vec<u8>>>,
+    reads: BTreeMap<[u8; 32], Option<Vec<u8>>>,
+}
 
-        // Read the message based on the length
-        if message_length > buffer.len() {
-            buffer.resize(message_length, 0);
+impl Transaction {
+    fn new(base_root: [u8; 32]) -> Self {
+        Transaction { base_root, writes: BTreeMap::new(), reads: BTreeMap::new() }
+    }
+
+    // A key that was both read and written becomes a single `ReadThenWrite`.
+    fn into_access_list(self) -> Vec<([u8; 32], KeyReadWrite)> {
+        let mut merged: BTreeMap<[u8; 32], KeyReadWrite> = BTreeMap::new();
+        for (key, value) in self.reads {
+            merged.insert(key, KeyReadWrite::Read(value));
         }
-        if stream.read_exact(&mut buffer[..message_length]).is_err() {
-            break;
+        for (key, write) in self.writes {
+            let entry = match merged.remove(&key) {
+                Some(KeyReadWrite::Read(read)) => KeyReadWrite::ReadThenWrite(read, write),
+                _ => KeyReadWrite::Write(write),
+            };
+            merged.insert(key, entry);
         }
-        // Deserialize the request
-        let request = Request::decode(&buffer[..message_length]).unwrap();
+        merged.into_iter().collect()
+    }
+}
 
-        // Example processing logic
-        let response = match request.request.unwrap() {
-            database_interface::request::Request::Root(_) => {
-                Response {
-                    err_code: 0,
-                    response: Some(database_interface::response::Response::Root(RootResponse {
-                        root: nomt.root().to_vec(),
-                    })),
+// Dispatch a single request. Reads and prefetches fan out to read-only views of
+// the committed trie (live, or pinned to a retained past root), while `Update`
+// and transaction commits are serialized through the one writer session held in
+// `state`. `txn` is this connection's pending transaction, if any. Returns the
+// response and whether the connection asked to be closed afterwards.
+fn process(request: Request, nomt: &Nomt<Blake3Hasher>, state: &mut ServerState, txn: &mut Option<Transaction>, import: &mut Option<ImportBuffer>) -> (Response, bool) {
+    match request.request.unwrap() {
+        database_interface::request::Request::Root(_) => {
+            let response = Response {
+                err_code: 0,
+                response: Some(database_interface::response::Response::Root(RootResponse {
+                    root: nomt.root().to_vec(),
+                })),
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Get(req) => {
+            let key_path = sha2::Sha256::digest(&req.key).into();
+            // Inside an open transaction a live read sees the staged overlay
+            // first, then the trie pinned to the transaction's base root.
+            if req.root.is_empty() {
+                if let Some(tx) = txn.as_mut() {
+                    // The overlay is uncommitted, so it has no root to prove
+                    // against.
+                    if req.prove {
+                        return (Response { err_code: 5, response: None }, false);
+                    }
+                    // `base_root` can age out of the history window while the
+                    // transaction stays open, same as a pinned `Get`/`Export`.
+                    if !tx.writes.contains_key(&key_path) && !state.retains(&tx.base_root) {
+                        return (Response { err_code: 2, response: None }, false);
+                    }
+                    let value = match tx.writes.get(&key_path) {
+                        Some(staged) => staged.clone(),
+                        None => {
+                            let value = nomt.read_only_session_at(tx.base_root).read(key_path).unwrap();
+                            tx.reads.entry(key_path).or_insert_with(|| value.clone());
+                            value
+                        }
+                    };
+                    let response = match value {
+                        Some(value) => Response {
+                            err_code: 0,
+                            response: Some(database_interface::response::Response::Get(GetResponse {
+                                value,
+                                proof: None,
+                            })),
+                        },
+                        None => Response { err_code: 1, response: None },
+                    };
+                    return (response, false);
                 }
             }
-            database_interface::request::Request::Get(req) => {
-                let key_path = sha2::Sha256::digest(&req.key).into();
-                let value = next_session.read(key_path).unwrap();
-                match value {
-                    Some(value) => {
+            // Resolve the view: live when no root is pinned, otherwise a view at
+            // the requested past root provided it is still retained.
+            let view = if req.root.is_empty() {
+                Some(nomt.read_only_session())
+            } else {
+                match root_array(&req.root) {
+                    Some(root) if state.retains(&root) => Some(nomt.read_only_session_at(root)),
+                    _ => None,
+                }
+            };
+            let response = match view {
+                // The requested root has been pruned out of the window.
+                None => Response { err_code: 2, response: None },
+                Some(view) => {
+                    let value = view.read(key_path).unwrap();
+                    if req.prove {
+                        // A proof attests membership or absence, so it is
+                        // returned for a missing key too (with an empty value).
+                        let proof = proof_from_path(key_path, view.prove(key_path));
                         Response {
                             err_code: 0,
                             response: Some(database_interface::response::Response::Get(GetResponse {
-                                value: value,
+                                value: value.unwrap_or_default(),
+                                proof: Some(proof),
                             })),
                         }
-                    }
-                    None => {
-                        Response {
-                            err_code: 1,
-                            response: None,
+                    } else {
+                        match value {
+                            Some(value) => Response {
+                                err_code: 0,
+                                response: Some(database_interface::response::Response::Get(GetResponse {
+                                    value: value,
+                                    proof: None,
+                                })),
+                            },
+                            None => Response {
+                                err_code: 1,
+                                response: None,
+                            },
                         }
                     }
                 }
-            }
-            database_interface::request::Request::Prefetch(req) => {
-                let key_path = sha2::Sha256::digest(&req.key).into();
-                next_session.warm_up(key_path);
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Prefetch(req) => {
+            let key_path = sha2::Sha256::digest(&req.key).into();
+            nomt.read_only_session().warm_up(key_path);
+            let response = Response {
+                err_code: 0,
+                response: Some(database_interface::response::Response::Prefetch(PrefetchResponse {})),
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Update(req) => {
+            let session = state.writer.take().expect("writer session present");
+            let mut actual_access: Vec<_> = req.items.into_iter().map(|item| {
+                let key_path = sha2::Sha256::digest(&item.key).into();
+                let write_val = match item.value.len() {
+                    0 => None,
+                    _ => Some(item.value),
+                };
+                (key_path, KeyReadWrite::Write(write_val))
+            }).collect();
+            actual_access.sort_by_key(|(k, _)| *k);
+
+            // Prove the touched keys against the prior root only when asked.
+            let multiproof = if req.prove {
+                let paths = actual_access
+                    .iter()
+                    .map(|(k, _)| (*k, session.prove(*k)))
+                    .collect();
+                Some(multiproof_from_paths(paths))
+            } else {
+                None
+            };
+
+            let root = nomt.commit(session, actual_access).unwrap();
+            state.writer = Some(nomt.begin_session());
+            state.record_root(root);
+            let response = Response {
+                err_code: 0,
+                response: Some(database_interface::response::Response::Update(UpdateResponse {
+                    root: root.to_vec(),
+                    multiproof,
+                })),
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Close(_) => {
+            let response = Response {
+                err_code: 0,
+                response: Some(database_interface::response::Response::Close(CloseResponse {})),
+            };
+            (response, true)
+        }
+        database_interface::request::Request::Prove(req) => {
+            let key_path = sha2::Sha256::digest(&req.key).into();
+            let proof = proof_from_path(key_path, nomt.read_only_session().prove(key_path));
+            let response = Response {
+                err_code: 0,
+                response: Some(database_interface::response::Response::Prove(ProveResponse {
+                    proof: Some(proof),
+                })),
+            };
+            (response, false)
+        }
+        database_interface::request::Request::View(req) => {
+            // Confirm a past root is still reachable before a client pins reads
+            // to it; error distinctly when it has been pruned.
+            let response = match root_array(&req.root) {
+                Some(root) if state.retains(&root) => Response {
+                    err_code: 0,
+                    response: Some(database_interface::response::Response::View(ViewResponse {
+                        root: root.to_vec(),
+                    })),
+                },
+                _ => Response { err_code: 2, response: None },
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Begin(_) => {
+            let response = if txn.is_some() {
+                // A transaction is already open on this connection.
+                Response { err_code: 3, response: None }
+            } else {
+                *txn = Some(Transaction::new(nomt.root().into()));
                 Response {
                     err_code: 0,
-                    response: Some(database_interface::response::Response::Prefetch(PrefetchResponse {})),
+                    response: Some(database_interface::response::Response::Begin(BeginResponse {})),
+                }
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Stage(req) => {
+            let response = match txn.as_mut() {
+                None => Response { err_code: 3, response: None },
+                Some(tx) => {
+                    for item in req.items {
+                        let key_path = sha2::Sha256::digest(&item.key).into();
+                        let write_val = match item.value.len() {
+                            0 => None,
+                            _ => Some(item.value),
+                        };
+                        tx.writes.insert(key_path, write_val);
+                    }
+                    Response {
+                        err_code: 0,
+                        response: Some(database_interface::response::Response::Stage(StageResponse {})),
+                    }
+                }
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Commit(_) => {
+            let response = match txn.take() {
+                None => Response { err_code: 3, response: None },
+                Some(tx) => {
+                    // Refuse the commit if the live root moved since `Begin`: the
+                    // staged read set was taken against `base_root`, so a commit
+                    // on top of a newer root could silently lose an update.
+                    let live: [u8; 32] = nomt.root().into();
+                    if live != tx.base_root {
+                        return (Response { err_code: 6, response: None }, false);
+                    }
+                    let access = tx.into_access_list();
+                    let session = state.writer.take().expect("writer session present");
+                    let root = nomt.commit(session, access).unwrap();
+                    state.writer = Some(nomt.begin_session());
+                    state.record_root(root);
+                    Response {
+                        err_code: 0,
+                        response: Some(database_interface::response::Response::Commit(CommitResponse {
+                            root: root.to_vec(),
+                        })),
+                    }
+                }
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Rollback(_) => {
+            let response = match txn.take() {
+                None => Response { err_code: 3, response: None },
+                Some(_) => Response {
+                    err_code: 0,
+                    response: Some(database_interface::response::Response::Rollback(RollbackResponse {})),
+                },
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Export(req) => {
+            let limit = if req.limit == 0 { DEFAULT_EXPORT_LIMIT } else { req.limit as usize };
+            // Pin the whole resumable stream to one root so concurrent writers
+            // can't make later chunks disagree with earlier ones.
+            let (root, view) = if req.root.is_empty() {
+                (nomt.root().into(), nomt.read_only_session())
+            } else {
+                match root_array(&req.root) {
+                    Some(root) if state.retains(&root) => (root, nomt.read_only_session_at(root)),
+                    _ => return (Response { err_code: 2, response: None }, false),
+                }
+            };
+            // Leaves are yielded in key-path order; seek straight to the
+            // cursor instead of rescanning every earlier chunk's leaves.
+            let after = root_array(&req.after);
+            let mut entries = Vec::new();
+            for (key_path, value) in view.iter_from(after) {
+                if after.is_some_and(|after| key_path <= after) {
+                    continue;
+                }
+                entries.push(SnapshotEntry { key_path: key_path.to_vec(), value });
+                if entries.len() >= limit {
+                    break;
                 }
             }
-            database_interface::request::Request::Update(req) => {
-                let mut actual_access: Vec<_> = req.items.into_iter().map(|item| {
-                    let key_path = sha2::Sha256::digest(&item.key).into();
-                    let write_val = match item.value.len() {
-                        0 => None,
-                        _ => Some(item.value),
-                    };
-                    (key_path, KeyReadWrite::Write(write_val))
-                }).collect();
-                actual_access.sort_by_key(|(k, _)| *k);
+            let done = entries.len() < limit;
+            let cursor = match entries.last() {
+                Some(last) if !done => last.key_path.clone(),
+                _ => Vec::new(),
+            };
+            let response = Response {
+                err_code: 0,
+                response: Some(database_interface::response::Response::Export(ExportResponse {
+                    root: root.to_vec(),
+                    entries,
+                    cursor,
+                    done,
+                })),
+            };
+            (response, false)
+        }
+        database_interface::request::Request::Import(req) => {
+            let buffer = import.get_or_insert_with(BTreeMap::new);
+            for entry in req.entries {
+                let key_path = match root_array(&entry.key_path) {
+                    Some(key_path) => key_path,
+                    // A malformed key path aborts the import rather than taking
+                    // down the whole loop. A dedicated code, distinct from the
+                    // pruned-root `err_code: 2`, so a client can tell its entry
+                    // was bad rather than its pinned root aging out.
+                    None => {
+                        *import = None;
+                        return (Response { err_code: 7, response: None }, false);
+                    }
+                };
+                let value = match entry.value.len() {
+                    0 => None,
+                    _ => Some(entry.value),
+                };
+                // Last write wins if a resent chunk repeats a key_path.
+                buffer.insert(key_path, value);
+            }
+            if !req.done {
+                let response = Response {
+                    err_code: 0,
+                    response: Some(database_interface::response::Response::Import(ImportResponse {
+                        root: Vec::new(),
+                        complete: false,
+                    })),
+                };
+                return (response, false);
+            }
 
-                let root= nomt.commit(next_session, actual_access).unwrap();
-                next_session = nomt.begin_session();
+            // Final chunk: rebuild the trie, and only keep the commit if it
+            // reproduces the declared root; otherwise roll it back. The
+            // buffer is already deduped and sorted by `key_path`.
+            let access: Vec<_> = import
+                .take()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(key, value)| (key, KeyReadWrite::Write(value)))
+                .collect();
+            let session = state.writer.take().expect("writer session present");
+            let root = nomt.commit(session, access).unwrap();
+            let response = if root.to_vec() == req.root {
+                state.writer = Some(nomt.begin_session());
+                state.record_root(root);
                 Response {
                     err_code: 0,
-                    response: Some(database_interface::response::Response::Update(UpdateResponse {
+                    response: Some(database_interface::response::Response::Import(ImportResponse {
                         root: root.to_vec(),
+                        complete: true,
                     })),
                 }
+            } else {
+                nomt.rollback(1).unwrap();
+                state.writer = Some(nomt.begin_session());
+                Response { err_code: 4, response: None }
+            };
+            (response, false)
+        }
+    }
+}
+
+// Per-connection state. Length-prefixed frames accumulate in `read_buf`;
+// responses queue in `write_buf` and drain from `write_pos` as the socket
+// becomes writable.
+struct Connection {
+    stream: UnixStream,
+    token: Token,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    closing: bool,
+    // The pending transaction opened on this connection, if any.
+    txn: Option<Transaction>,
+    // Leaves buffered for an in-progress snapshot import, if any.
+    import: Option<ImportBuffer>,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, token: Token) -> Self {
+        Connection {
+            stream,
+            token,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            write_pos: 0,
+            closing: false,
+            txn: None,
+            import: None,
+        }
+    }
+
+    fn interest(&self) -> Interest {
+        if self.write_pos < self.write_buf.len() {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        }
+    }
+
+    // Drain the socket into `read_buf`. Returns false once the peer has hung up.
+    fn fill(&mut self) -> bool {
+        let mut chunk = [0u8; 64 * 1024];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return false,
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false,
             }
-            database_interface::request::Request::Close(_) => {
-                Response {
-                    err_code: 0,
-                    response: Some(database_interface::response::Response::Close(CloseResponse {})),
-                }
+        }
+    }
+
+    // Pull every complete frame out of `read_buf`, leaving any trailing partial
+    // frame in place for the next wakeup. A claimed length over
+    // `MAX_FRAME_LEN` closes the connection instead of growing `read_buf` to
+    // match it.
+    fn take_frames(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while self.read_buf.len() - offset >= 4 {
+            let len = u32::from_be_bytes(self.read_buf[offset..offset + 4].try_into().unwrap()) as usize;
+            if len > MAX_FRAME_LEN {
+                self.closing = true;
+                break;
             }
-        };
+            if self.read_buf.len() - offset - 4 < len {
+                break;
+            }
+            let start = offset + 4;
+            frames.push(self.read_buf[start..start + len].to_vec());
+            offset = start + len;
+        }
+        if offset > 0 {
+            self.read_buf.drain(..offset);
+        }
+        frames
+    }
+
+    // Frame responses the same way requests arrive: a 4-byte big-endian
+    // length prefix, so a client decoding one message per read can't have two
+    // pipelined responses run together in a single write.
+    fn queue(&mut self, response: &Response) {
+        let start = self.write_buf.len();
+        self.write_buf.extend_from_slice(&[0u8; 4]);
+        response.encode(&mut self.write_buf).unwrap();
+        let len = (self.write_buf.len() - start - 4) as u32;
+        self.write_buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    }
 
-        // Serialize the response
-        let mut response_buffer = Vec::new();
-        response.encode(&mut response_buffer).unwrap();
-        stream.write_all(&response_buffer).unwrap();
-    };
-    
+    fn flush(&mut self) -> io::Result<()> {
+        while self.write_pos < self.write_buf.len() {
+            match self.stream.write(&self.write_buf[self.write_pos..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => self.write_pos += n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.write_pos == self.write_buf.len() {
+            self.write_buf.clear();
+            self.write_pos = 0;
+        }
+        Ok(())
+    }
 
-    // Return the session to be used in the next iteration
-    next_session
+    fn is_done(&self) -> bool {
+        self.closing && self.write_pos == self.write_buf.len()
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -132,6 +628,10 @@ struct Args {
     // Number of hashtable buckets
     #[arg(short, long, default_value = "64000")]
     hashtable_buckets: u32,
+
+    /// Number of recent committed roots kept reachable for historical reads
+    #[arg(long, default_value = "256")]
+    history_window: usize,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -141,7 +641,7 @@ fn main() -> anyhow::Result<()> {
         std::fs::remove_file(socket_path)?;
     }
 
-    let listener = UnixListener::bind(socket_path)?;
+    let mut listener = UnixListener::bind(socket_path)?;
     println!("Server listening on {}", socket_path);
 
 
@@ -150,22 +650,98 @@ fn main() -> anyhow::Result<()> {
     opts.io_workers(args.io_workers);
     opts.commit_concurrency(args.commit_concurrency);
     opts.path(args.path);
-    opts.hashtable_buckets(hashtable_buckets);
+    opts.hashtable_buckets(args.hashtable_buckets);
+    // Retain enough revertible commits for the historical-read window; this is
+    // also what lets a failed snapshot import undo its commit.
+    let history_window = args.history_window.max(1);
+    opts.rollback(history_window);
 
     let nomt = Nomt::<Blake3Hasher>::open(opts)?;
-    let mut session = nomt.begin_session();
-
-    // Main loop to handle client connections
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                session =  handle_client(stream, &nomt, session);
-            },
-            Err(err) => {
-                eprintln!("Connection failed: {}", err);
+    let mut state = ServerState::new(nomt.begin_session(), nomt.root().into(), history_window);
+
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(1024);
+    poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+
+    let mut connections: Slab<Connection> = Slab::new();
+
+    loop {
+        poll.poll(&mut events, None)?;
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => {
+                    // Drain the accept backlog before polling again.
+                    loop {
+                        match listener.accept() {
+                            Ok((mut stream, _addr)) => {
+                                let entry = connections.vacant_entry();
+                                let token = Token(entry.key() + CONN_OFFSET);
+                                poll.registry().register(&mut stream, token, Interest::READABLE)?;
+                                entry.insert(Connection::new(stream, token));
+                            }
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(err) => {
+                                eprintln!("Connection failed: {}", err);
+                                break;
+                            }
+                        }
+                    }
+                }
+                token => {
+                    let index = token.0 - CONN_OFFSET;
+                    if !connections.contains(index) {
+                        continue;
+                    }
+
+                    let mut drop_conn = false;
+                    if event.is_readable() {
+                        // Process buffered frames before honouring a hangup seen
+                        // in the same readable burst.
+                        let alive = connections[index].fill();
+                        for frame in connections[index].take_frames() {
+                            // A malformed frame only costs this connection; it
+                            // must not take the whole event loop down with it.
+                            let request = match Request::decode(&frame[..]) {
+                                Ok(request) => request,
+                                Err(err) => {
+                                    eprintln!("Malformed request on {:?}: {}", connections[index].token, err);
+                                    connections[index].closing = true;
+                                    break;
+                                }
+                            };
+                            let conn = &mut connections[index];
+                            let (response, close) = process(request, &nomt, &mut state, &mut conn.txn, &mut conn.import);
+                            conn.queue(&response);
+                            if close {
+                                conn.closing = true;
+                            }
+                        }
+                        if !alive {
+                            connections[index].closing = true;
+                        }
+                    }
+
+                    if connections.contains(index) && event.is_writable() {
+                        if connections[index].flush().is_err() {
+                            drop_conn = true;
+                        }
+                    }
+
+                    if let Some(conn) = connections.get_mut(index) {
+                        if conn.flush().is_err() {
+                            drop_conn = true;
+                        }
+                        if drop_conn || conn.is_done() {
+                            let mut conn = connections.remove(index);
+                            let _ = poll.registry().deregister(&mut conn.stream);
+                        } else {
+                            let interest = conn.interest();
+                            poll.registry().reregister(&mut conn.stream, conn.token, interest)?;
+                        }
+                    }
+                }
             }
         }
     }
-
-    Ok(())
 }